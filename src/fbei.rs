@@ -0,0 +1,484 @@
+//! Full Environment Interface (FBEI): bridges the EVMC host context into the
+//! handful of primitives the `bcos` wasm imports need (storage, calldata,
+//! logs, sub-calls, ...).
+use evmc_vm::ffi::{evmc_call_kind, evmc_flags, evmc_status_code};
+use evmc_vm::{Address, ExecutionContext, ExecutionMessage};
+use wasmtime::Memory;
+
+/// Owns the pieces of execution state that the `bcos.*` host functions need
+/// to read or mutate while a contract is running. Lives behind
+/// `Arc<Mutex<_>>` in `lib.rs` so every `func_wrap` closure can reach it from
+/// the `Caller`.
+///
+/// `context`/`message` are erased to `'static` with a raw pointer because
+/// `wasmtime::Store<T>` requires `T: 'static`, while the EVMC context is only
+/// valid for the duration of a single `execute` call; callers must ensure the
+/// `EnvironmentInterface` is dropped before the borrowed context goes away,
+/// which `execute` guarantees by construction.
+pub struct EnvironmentInterface {
+    context: *mut ExecutionContext<'static>,
+    message: *const ExecutionMessage<'static>,
+    memory: Option<Memory>,
+    /// Gas remaining, read and written by the `gasGet`/`gasSet` host calls
+    /// the instrumentation pass injects. Lives here instead of as a
+    /// store-owned `Global` so a single `Linker`/module instance doesn't
+    /// need to be rebuilt per execution just to rebind that global.
+    gas: i64,
+    output: Vec<u8>,
+    reverted: bool,
+    /// Set when `message` carries `EVMC_STATIC` (directly, or inherited from
+    /// a `callStatic` our caller entered us through). All state-mutating EEI
+    /// calls must refuse to run while this is set.
+    read_only: bool,
+}
+
+unsafe impl Send for EnvironmentInterface {}
+
+impl EnvironmentInterface {
+    pub fn new<'a>(context: &'a mut ExecutionContext<'a>, message: &'a ExecutionMessage<'a>) -> Self {
+        let read_only = message.flags() & evmc_flags::EVMC_STATIC as u32 != 0;
+        EnvironmentInterface {
+            context: unsafe {
+                std::mem::transmute::<*mut ExecutionContext<'a>, *mut ExecutionContext<'static>>(
+                    context as *mut _,
+                )
+            },
+            message: unsafe {
+                std::mem::transmute::<*const ExecutionMessage<'a>, *const ExecutionMessage<'static>>(
+                    message as *const _,
+                )
+            },
+            memory: None,
+            gas: message.gas(),
+            output: Vec::new(),
+            reverted: false,
+            read_only,
+        }
+    }
+
+    fn context(&mut self) -> &mut ExecutionContext<'static> {
+        unsafe { &mut *self.context }
+    }
+
+    fn message(&self) -> &ExecutionMessage<'static> {
+        unsafe { &*self.message }
+    }
+
+    pub fn set_memory(&mut self, memory: Memory) {
+        self.memory = Some(memory);
+    }
+
+    pub fn reverted(&self) -> bool {
+        self.reverted
+    }
+
+    pub fn get_output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Read by the injected `call $gasGet` and by the public `getGasLeft`
+    /// EEI function.
+    pub fn get_gas_left(&self) -> i64 {
+        self.gas
+    }
+
+    /// Written by the injected `call $gasSet` that follows every metered
+    /// block's charge computation.
+    pub fn set_gas_left(&mut self, gas: i64) {
+        self.gas = gas;
+    }
+
+    fn read_memory(&self, caller: &impl wasmtime::AsContext, offset: u32, size: u32) -> anyhow::Result<Vec<u8>> {
+        let memory = self.memory.as_ref().unwrap();
+        let mut buffer = vec![0u8; size as usize];
+        memory.read(caller, offset as usize, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn write_memory(&self, caller: &mut impl wasmtime::AsContextMut, offset: u32, data: &[u8]) -> anyhow::Result<()> {
+        let memory = self.memory.as_ref().unwrap();
+        memory.write(caller, offset as usize, data)?;
+        Ok(())
+    }
+
+    pub fn finish(&mut self, caller: &impl wasmtime::AsContext, data_offset: u32, data_size: u32) -> anyhow::Result<()> {
+        self.output = self.read_memory(caller, data_offset, data_size)?;
+        self.reverted = false;
+        Ok(())
+    }
+
+    pub fn revert(&mut self, caller: &impl wasmtime::AsContext, data_offset: u32, data_size: u32) -> anyhow::Result<()> {
+        self.output = self.read_memory(caller, data_offset, data_size)?;
+        self.reverted = true;
+        Ok(())
+    }
+
+    pub fn get_address(&mut self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<i32> {
+        let address = *self.message().destination();
+        self.write_memory(caller, result_offset, &address.bytes)?;
+        Ok(address.bytes.len() as i32)
+    }
+
+    pub fn get_call_data_size(&self) -> anyhow::Result<i32> {
+        Ok(self.message().input_data().len() as i32)
+    }
+
+    pub fn get_call_data(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<()> {
+        let input = self.message().input_data().to_vec();
+        self.write_memory(caller, result_offset, &input)
+    }
+
+    pub fn set_storage(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        key_offset: u32,
+        key_size: u32,
+        value_offset: u32,
+        value_size: u32,
+    ) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow::anyhow!("state modification in static context"));
+        }
+        let key = self.read_memory(caller, key_offset, key_size)?;
+        let value = self.read_memory(caller, value_offset, value_size)?;
+        let address = *self.message().destination();
+        self.context().set_storage(&address, &key, &value);
+        Ok(())
+    }
+
+    pub fn get_storage(
+        &self,
+        caller: &mut impl wasmtime::AsContextMut,
+        key_offset: u32,
+        key_size: u32,
+        value_offset: u32,
+        max_value_size: u32,
+    ) -> anyhow::Result<i32> {
+        let key = self.read_memory(caller, key_offset, key_size)?;
+        let address = *self.message().destination();
+        let value = unsafe { &mut *self.context }.get_storage(&address, &key);
+        let len = std::cmp::min(value.len(), max_value_size as usize);
+        self.write_memory(caller, value_offset, &value[..len])?;
+        Ok(len as i32)
+    }
+
+    pub fn get_caller(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<i32> {
+        let sender = *self.message().sender();
+        self.write_memory(caller, result_offset, &sender.bytes)?;
+        Ok(sender.bytes.len() as i32)
+    }
+
+    pub fn get_tx_origin(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<i32> {
+        let tx_context = unsafe { &*self.context }.get_tx_context();
+        self.write_memory(caller, result_offset, &tx_context.tx_origin.bytes)?;
+        Ok(tx_context.tx_origin.bytes.len() as i32)
+    }
+
+    pub fn get_code_size(&mut self, caller: &mut impl wasmtime::AsContextMut, address_offset: u32, _size: u32) -> anyhow::Result<i32> {
+        let address = Self::to_address(&self.read_memory(caller, address_offset, 20)?);
+        Ok(self.context().get_code_size(&address) as i32)
+    }
+
+    pub fn get_block_number(&self) -> i64 {
+        unsafe { &*self.context }.get_tx_context().block_number
+    }
+
+    pub fn get_block_timestamp(&self) -> i64 {
+        unsafe { &*self.context }.get_tx_context().block_timestamp
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn log(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        data_offset: u32,
+        data_size: u32,
+        number_of_topics: i32,
+        topic1: u32,
+        topic2: u32,
+        topic3: u32,
+        topic4: u32,
+    ) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow::anyhow!("state modification in static context"));
+        }
+        let data = self.read_memory(caller, data_offset, data_size)?;
+        let topic_offsets = [topic1, topic2, topic3, topic4];
+        let mut topics = Vec::with_capacity(number_of_topics as usize);
+        for &offset in topic_offsets.iter().take(number_of_topics as usize) {
+            let bytes = self.read_memory(caller, offset, 32)?;
+            let mut topic = evmc_vm::Bytes32::default();
+            topic.bytes.copy_from_slice(&bytes);
+            topics.push(topic);
+        }
+        let address = *self.message().destination();
+        self.context().emit_log(&address, &data, &topics);
+        Ok(())
+    }
+
+    pub fn get_return_data_size(&self) -> i32 {
+        self.output.len() as i32
+    }
+
+    pub fn get_return_data(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<()> {
+        let output = self.output.clone();
+        self.write_memory(caller, result_offset, &output)
+    }
+
+    fn to_address(bytes: &[u8]) -> Address {
+        let mut address = Address::default();
+        let len = std::cmp::min(bytes.len(), address.bytes.len());
+        address.bytes[..len].copy_from_slice(&bytes[..len]);
+        address
+    }
+
+    pub fn get_block_hash(&mut self, caller: &mut impl wasmtime::AsContextMut, number: i64, result_offset: u32) -> anyhow::Result<()> {
+        let hash = self.context().get_block_hash(number);
+        self.write_memory(caller, result_offset, &hash.bytes)
+    }
+
+    pub fn get_block_coinbase(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<()> {
+        let coinbase = unsafe { &*self.context }.get_tx_context().block_coinbase;
+        self.write_memory(caller, result_offset, &coinbase.bytes)
+    }
+
+    pub fn get_block_gas_limit(&self) -> i64 {
+        unsafe { &*self.context }.get_tx_context().block_gas_limit
+    }
+
+    pub fn get_block_prev_randao(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<()> {
+        let prev_randao = unsafe { &*self.context }.get_tx_context().block_prev_randao;
+        self.write_memory(caller, result_offset, &prev_randao.bytes)
+    }
+
+    pub fn get_tx_gas_price(&self, caller: &mut impl wasmtime::AsContextMut, result_offset: u32) -> anyhow::Result<()> {
+        let gas_price = unsafe { &*self.context }.get_tx_context().tx_gas_price;
+        self.write_memory(caller, result_offset, &gas_price.bytes)
+    }
+
+
+    pub fn get_external_balance(&mut self, caller: &mut impl wasmtime::AsContextMut, address_offset: u32, result_offset: u32) -> anyhow::Result<()> {
+        let address = Self::to_address(&self.read_memory(caller, address_offset, 20)?);
+        let balance = self.context().get_balance(&address);
+        self.write_memory(caller, result_offset, &balance.bytes)
+    }
+
+    pub fn get_external_code_hash(&mut self, caller: &mut impl wasmtime::AsContextMut, address_offset: u32, result_offset: u32) -> anyhow::Result<()> {
+        let address = Self::to_address(&self.read_memory(caller, address_offset, 20)?);
+        let hash = self.context().get_code_hash(&address);
+        self.write_memory(caller, result_offset, &hash.bytes)
+    }
+
+    pub fn external_code_copy(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        address_offset: u32,
+        code_offset: u32,
+        result_offset: u32,
+        size: u32,
+    ) -> anyhow::Result<i32> {
+        let address = Self::to_address(&self.read_memory(caller, address_offset, 20)?);
+        let mut buffer = vec![0u8; size as usize];
+        let copied = self
+            .context()
+            .copy_code(&address, code_offset as usize, &mut buffer);
+        self.write_memory(caller, result_offset, &buffer[..copied])?;
+        Ok(copied as i32)
+    }
+
+    pub fn self_destruct(&mut self, caller: &mut impl wasmtime::AsContextMut, address_offset: u32) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(anyhow::anyhow!("state modification in static context"));
+        }
+        let beneficiary = Self::to_address(&self.read_memory(caller, address_offset, 20)?);
+        let destination = *self.message().destination();
+        self.context().selfdestruct(&destination, &beneficiary);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn dispatch_call(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        kind: evmc_call_kind,
+        is_static: bool,
+        address_offset: u32,
+        address_size: u32,
+        data_offset: u32,
+        data_size: u32,
+    ) -> anyhow::Result<i32> {
+        let address = Self::to_address(&self.read_memory(caller, address_offset, address_size)?);
+        let input = self.read_memory(caller, data_offset, data_size)?;
+        let gas_left = self.get_gas_left();
+
+        let mut flags = 0u32;
+        // A static sub-call, or simply being in a read-only context
+        // ourselves, both force the callee to inherit the restriction.
+        if is_static || self.read_only {
+            flags |= evmc_flags::EVMC_STATIC as u32;
+        }
+        let destination = *self.message().destination();
+        let sender = *self.message().sender();
+        let (destination, sender, code_address) = match kind {
+            evmc_call_kind::EVMC_DELEGATECALL => (destination, sender, address),
+            evmc_call_kind::EVMC_CALLCODE => (destination, destination, address),
+            _ => (address, destination, address),
+        };
+        let message = ExecutionMessage::new(
+            kind,
+            flags,
+            self.message().depth() + 1,
+            gas_left,
+            destination,
+            sender,
+            &input,
+            &Default::default(),
+            Default::default(),
+            code_address,
+        );
+        let result = self.context().call(&message);
+        self.output = result.output().map(|o| o.to_vec()).unwrap_or_default();
+        Ok(result.status_code() as i32)
+    }
+
+    pub fn call(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        address_offset: u32,
+        address_size: u32,
+        data_offset: u32,
+        data_size: u32,
+    ) -> anyhow::Result<i32> {
+        self.dispatch_call(
+            caller,
+            evmc_call_kind::EVMC_CALL,
+            false,
+            address_offset,
+            address_size,
+            data_offset,
+            data_size,
+        )
+    }
+
+    pub fn call_code(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        address_offset: u32,
+        address_size: u32,
+        data_offset: u32,
+        data_size: u32,
+    ) -> anyhow::Result<i32> {
+        self.dispatch_call(
+            caller,
+            evmc_call_kind::EVMC_CALLCODE,
+            false,
+            address_offset,
+            address_size,
+            data_offset,
+            data_size,
+        )
+    }
+
+    pub fn call_delegate(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        address_offset: u32,
+        address_size: u32,
+        data_offset: u32,
+        data_size: u32,
+    ) -> anyhow::Result<i32> {
+        self.dispatch_call(
+            caller,
+            evmc_call_kind::EVMC_DELEGATECALL,
+            false,
+            address_offset,
+            address_size,
+            data_offset,
+            data_size,
+        )
+    }
+
+    pub fn call_static(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        address_offset: u32,
+        address_size: u32,
+        data_offset: u32,
+        data_size: u32,
+    ) -> anyhow::Result<i32> {
+        self.dispatch_call(
+            caller,
+            evmc_call_kind::EVMC_CALL,
+            true,
+            address_offset,
+            address_size,
+            data_offset,
+            data_size,
+        )
+    }
+
+    pub fn create(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        data_offset: u32,
+        data_size: u32,
+        result_offset: u32,
+    ) -> anyhow::Result<i32> {
+        self.dispatch_create(caller, data_offset, data_size, None, result_offset)
+    }
+
+    pub fn create2(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        data_offset: u32,
+        data_size: u32,
+        salt_offset: u32,
+        result_offset: u32,
+    ) -> anyhow::Result<i32> {
+        let salt = self.read_memory(caller, salt_offset, 32)?;
+        let mut salt_bytes = evmc_vm::Bytes32::default();
+        salt_bytes.bytes.copy_from_slice(&salt);
+        self.dispatch_create(caller, data_offset, data_size, Some(salt_bytes), result_offset)
+    }
+
+    fn dispatch_create(
+        &mut self,
+        caller: &mut impl wasmtime::AsContextMut,
+        data_offset: u32,
+        data_size: u32,
+        salt: Option<evmc_vm::Bytes32>,
+        result_offset: u32,
+    ) -> anyhow::Result<i32> {
+        if self.read_only {
+            return Err(anyhow::anyhow!("state modification in static context"));
+        }
+        let init_code = self.read_memory(caller, data_offset, data_size)?;
+        let gas_left = self.get_gas_left();
+        let kind = if salt.is_some() {
+            evmc_call_kind::EVMC_CREATE2
+        } else {
+            evmc_call_kind::EVMC_CREATE
+        };
+        let destination = *self.message().destination();
+        let message = ExecutionMessage::new(
+            kind,
+            0,
+            self.message().depth() + 1,
+            gas_left,
+            Address::default(),
+            destination,
+            &init_code,
+            &Default::default(),
+            salt.unwrap_or_default(),
+            Address::default(),
+        );
+        let result = self.context().call(&message);
+        self.output = result.output().map(|o| o.to_vec()).unwrap_or_default();
+        if result.status_code() == evmc_status_code::EVMC_SUCCESS {
+            if let Some(created) = result.create_address() {
+                self.write_memory(caller, result_offset, &created.bytes)?;
+            }
+        }
+        Ok(result.status_code() as i32)
+    }
+}
@@ -0,0 +1,367 @@
+//! Load-time gas instrumentation, in the style of parity's `wasm-utils` gas
+//! injector: every contract is rewritten at `execute` time, before it is
+//! compiled or looked up in the module cache, so gas
+//! accounting no longer depends on the contract's own bytecode cooperating
+//! with the host.
+use parity_wasm::elements::{BlockType, External, FuncBody, Instruction, Module, Type, ValueType};
+
+use crate::BCOS_MODULE_NAME;
+
+/// Gas now lives in host-owned store data rather than a store-owned
+/// `Global` (see `EnvironmentInterface::gas`), so the charge sequence reads
+/// and writes it through these two calls instead of `global.get`/`global.set`.
+pub const GAS_GET_IMPORT: &str = "gasGet";
+pub const GAS_SET_IMPORT: &str = "gasSet";
+/// `instrument` rejects any module that does not import this triple, and
+/// `validation::validate` rejects it as well (see
+/// `validation::REQUIRED_GAS_IMPORTS`); `instrument` looks them up by name
+/// rather than assuming fixed indices.
+pub const OUT_OF_GAS_IMPORT: &str = "outOfGas";
+
+/// Per-opcode gas prices. Kept as a flat table so operators can retune costs
+/// without touching the injector itself.
+#[derive(Clone, Copy, Debug)]
+pub struct CostTable {
+    pub default: u32,
+    pub call: u32,
+    pub call_indirect: u32,
+    pub memory_grow_per_page: u32,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            default: 1,
+            call: 10,
+            call_indirect: 20,
+            memory_grow_per_page: 1000,
+        }
+    }
+}
+
+impl CostTable {
+    fn cost_of(&self, instruction: &Instruction) -> u32 {
+        match instruction {
+            Instruction::Call(_) => self.call,
+            Instruction::CallIndirect(_, _) => self.call_indirect,
+            _ => self.default,
+        }
+    }
+}
+
+/// A contiguous run of instructions that always executes as a unit: gas for
+/// the whole block is charged once, at block entry.
+struct MeteredBlock {
+    /// Index of the first instruction belonging to this block.
+    start: usize,
+    /// Index one past the last instruction belonging to this block, in the
+    /// *original* (pre-instrumentation) numbering. Blocks are a contiguous
+    /// partition of the body, so this is fixed at partition time and must
+    /// not be recomputed from the (by-then-mutated) instruction vector.
+    end: usize,
+    cost: u64,
+}
+
+fn starts_new_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Block(_)
+            | Instruction::Loop(_)
+            | Instruction::If(_)
+            | Instruction::Else
+            | Instruction::End
+    )
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Br(_)
+            | Instruction::BrIf(_)
+            | Instruction::BrTable(_)
+            | Instruction::Call(_)
+            | Instruction::CallIndirect(_, _)
+    )
+}
+
+/// Partition `body` into metered blocks: one starting at the function entry,
+/// and one immediately after every branch/call/block-header/`end` instruction.
+fn metered_blocks(body: &[Instruction], costs: &CostTable) -> Vec<MeteredBlock> {
+    let mut blocks = Vec::new();
+    let mut current_start = 0usize;
+    let mut current_cost: u64 = 0;
+
+    for (index, instruction) in body.iter().enumerate() {
+        current_cost += costs.cost_of(instruction) as u64;
+        if instruction == &Instruction::End || instruction == &Instruction::Else {
+            // Block headers and the instructions they guard still belong to
+            // the block that contains them; `loop`/`block`/`if` themselves
+            // open a new metered block starting at the NEXT instruction.
+        }
+        let next_starts_block = starts_new_block(instruction);
+        let this_ends_block = ends_block(instruction);
+        if this_ends_block || next_starts_block {
+            blocks.push(MeteredBlock {
+                start: current_start,
+                end: index + 1,
+                cost: current_cost,
+            });
+            current_start = index + 1;
+            current_cost = 0;
+        }
+    }
+    if current_start < body.len() {
+        blocks.push(MeteredBlock {
+            start: current_start,
+            end: body.len(),
+            cost: current_cost,
+        });
+    }
+    blocks
+}
+
+/// `call $gasGet; i64.const cost; i64.sub; local.tee $scratch;
+///  call $gasSet; call $gasGet; i64.const 0; i64.lt_s;
+///  if (call $outOfGas) end`
+fn charge_sequence(
+    gas_get_func: u32,
+    gas_set_func: u32,
+    out_of_gas_func: u32,
+    scratch_local: u32,
+    cost: u64,
+) -> Vec<Instruction> {
+    vec![
+        Instruction::Call(gas_get_func),
+        Instruction::I64Const(cost as i64),
+        Instruction::I64Sub,
+        Instruction::TeeLocal(scratch_local),
+        Instruction::Call(gas_set_func),
+        Instruction::Call(gas_get_func),
+        Instruction::I64Const(0),
+        Instruction::I64LtS,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Call(out_of_gas_func),
+        Instruction::End,
+    ]
+}
+
+/// Charge for `memory.grow` proportional to the requested page count,
+/// without consuming the operand the contract pushed: `local.tee` stashes a
+/// copy, multiplies by the per-page cost, runs the same charge sequence,
+/// then replays the original operand for the real `memory.grow`. The cost is
+/// stashed into `cost_scratch` first so the subtraction below reads
+/// `gas - cost`, same operand order as `charge_sequence`.
+fn charge_memory_grow(
+    gas_get_func: u32,
+    gas_set_func: u32,
+    out_of_gas_func: u32,
+    pages_scratch: u32,
+    cost_scratch: u32,
+    per_page_cost: u32,
+) -> Vec<Instruction> {
+    vec![
+        Instruction::TeeLocal(pages_scratch),
+        Instruction::I64ExtendUI32,
+        Instruction::I64Const(per_page_cost as i64),
+        Instruction::I64Mul,
+        Instruction::SetLocal(cost_scratch),
+        Instruction::Call(gas_get_func),
+        Instruction::GetLocal(cost_scratch),
+        Instruction::I64Sub,
+        Instruction::TeeLocal(cost_scratch),
+        Instruction::Call(gas_set_func),
+        Instruction::Call(gas_get_func),
+        Instruction::I64Const(0),
+        Instruction::I64LtS,
+        Instruction::If(BlockType::NoResult),
+        Instruction::Call(out_of_gas_func),
+        Instruction::End,
+        Instruction::GetLocal(pages_scratch),
+    ]
+}
+
+/// Param count for each function in `module.code_section()`, in the same
+/// order (the function and code sections are parallel arrays indexed by
+/// defined-function index). Needed because the local index space is params
+/// first, then declared locals: a scratch local appended to `body.locals()`
+/// must skip past the params or it aliases a real parameter index.
+fn defined_function_param_counts(module: &Module) -> Vec<u32> {
+    let (Some(function_section), Some(type_section)) = (module.function_section(), module.type_section())
+    else {
+        return Vec::new();
+    };
+    function_section
+        .entries()
+        .iter()
+        .map(|func| {
+            let Type::Function(function_type) = &type_section.types()[func.type_ref() as usize];
+            function_type.params().len() as u32
+        })
+        .collect()
+}
+
+fn find_import(module: &Module, module_name: &str, field: &str) -> Option<u32> {
+    let mut index = 0u32;
+    if let Some(section) = module.import_section() {
+        for entry in section.entries() {
+            if let External::Function(_) = entry.external() {
+                if entry.module() == module_name && entry.field() == field {
+                    return Some(index);
+                }
+                index += 1;
+            }
+        }
+    }
+    None
+}
+
+#[allow(clippy::too_many_arguments)]
+fn instrument_body(
+    body: &mut FuncBody,
+    param_count: u32,
+    gas_get_func: u32,
+    gas_set_func: u32,
+    out_of_gas_func: u32,
+    costs: &CostTable,
+) -> anyhow::Result<()> {
+    // Local index space is params first, then declared locals (wasm binary
+    // format), so the new scratch locals start past both.
+    let scratch_local = param_count + body.locals().iter().map(|l| l.count()).sum::<u32>();
+    // `memory.grow`'s page-count operand is i32; only the gas/cost scratch
+    // needs the i64 width gas values are carried in.
+    body.locals_mut().push(parity_wasm::elements::Local::new(1, ValueType::I32));
+    body.locals_mut().push(parity_wasm::elements::Local::new(1, ValueType::I64));
+    let pages_scratch = scratch_local;
+    let cost_scratch = scratch_local + 1;
+
+    let instructions = body.code_mut().elements_mut();
+    let blocks = metered_blocks(instructions, costs);
+
+    // Walk blocks back-to-front so earlier insertions don't shift the start
+    // offsets of blocks we haven't processed yet.
+    for block in blocks.into_iter().rev() {
+        let mut charge = charge_sequence(gas_get_func, gas_set_func, out_of_gas_func, cost_scratch, block.cost);
+        // memory.grow inside this block still needs its own per-page charge,
+        // inserted immediately before the instruction. Bounded by the
+        // block's own (pre-instrumentation) end, not `instructions.len()`:
+        // blocks with a higher start are processed first and splice extra
+        // instructions into the vector, so re-reading `instructions.len()`
+        // here would let this block's scan bleed into already-instrumented
+        // tail belonging to a later block.
+        for offset in (block.start..block.end).rev() {
+            if instructions.get(offset) == Some(&Instruction::GrowMemory(0)) {
+                let extra = charge_memory_grow(
+                    gas_get_func,
+                    gas_set_func,
+                    out_of_gas_func,
+                    pages_scratch,
+                    cost_scratch,
+                    costs.memory_grow_per_page,
+                );
+                for (i, ins) in extra.into_iter().enumerate() {
+                    instructions.insert(offset + i, ins);
+                }
+            }
+        }
+        for (i, ins) in charge.drain(..).enumerate() {
+            instructions.insert(block.start + i, ins);
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite `code` so every function self-charges gas and self-traps via the
+/// host-owned `outOfGas` import, making metering mandatory instead of
+/// advisory. `execute` calls this before any validation, so it is the first
+/// check a module without the `bcos.gasGet`/`bcos.gasSet`/`bcos.outOfGas`
+/// triple meets: such a module makes this error out rather than silently
+/// skipping instrumentation.
+pub fn instrument(code: &[u8], costs: &CostTable) -> anyhow::Result<Vec<u8>> {
+    let mut module: Module = parity_wasm::deserialize_buffer(code)
+        .map_err(|e| anyhow::anyhow!("failed to parse wasm for gas instrumentation: {}", e))?;
+
+    let gas_get_func = find_import(&module, BCOS_MODULE_NAME, GAS_GET_IMPORT).ok_or_else(|| {
+        anyhow::anyhow!("module is missing required import {}.{}", BCOS_MODULE_NAME, GAS_GET_IMPORT)
+    })?;
+    let gas_set_func = find_import(&module, BCOS_MODULE_NAME, GAS_SET_IMPORT).ok_or_else(|| {
+        anyhow::anyhow!("module is missing required import {}.{}", BCOS_MODULE_NAME, GAS_SET_IMPORT)
+    })?;
+    let out_of_gas_func = find_import(&module, BCOS_MODULE_NAME, OUT_OF_GAS_IMPORT).ok_or_else(|| {
+        anyhow::anyhow!("module is missing required import {}.{}", BCOS_MODULE_NAME, OUT_OF_GAS_IMPORT)
+    })?;
+
+    let param_counts = defined_function_param_counts(&module);
+    if let Some(code_section) = module.code_section_mut() {
+        for (index, body) in code_section.bodies_mut().iter_mut().enumerate() {
+            let param_count = param_counts.get(index).copied().unwrap_or(0);
+            instrument_body(body, param_count, gas_get_func, gas_set_func, out_of_gas_func, costs)?;
+        }
+    }
+
+    parity_wasm::serialize(module)
+        .map_err(|e| anyhow::anyhow!("failed to re-encode instrumented wasm: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_wasm::elements::{
+        CodeSection, Func, FuncBody, FunctionSection, FunctionType, ImportEntry, ImportSection,
+        Instructions, MemorySection, MemoryType, Section, TypeSection,
+    };
+
+    /// Builds a module with one `bcos`-imported function per gas import and
+    /// one defined function that takes an `i32` param and calls
+    /// `memory.grow` on it, the exact shape that exposed the local-index and
+    /// scratch-local-type bugs: a defined function with a parameter aliases
+    /// the scratch locals unless `instrument` accounts for the param count,
+    /// and `memory.grow`'s operand is `i32` while gas values are `i64`.
+    fn module_with_param_and_memory_grow() -> Vec<u8> {
+        let gas_get_type = Type::Function(FunctionType::new(vec![], vec![ValueType::I64]));
+        let gas_set_type = Type::Function(FunctionType::new(vec![ValueType::I64], vec![]));
+        let out_of_gas_type = Type::Function(FunctionType::new(vec![], vec![]));
+        let contract_type = Type::Function(FunctionType::new(vec![ValueType::I32], vec![]));
+
+        let type_section = TypeSection::with_types(vec![
+            gas_get_type,
+            gas_set_type,
+            out_of_gas_type,
+            contract_type,
+        ]);
+        let import_section = ImportSection::with_entries(vec![
+            ImportEntry::new(BCOS_MODULE_NAME.to_string(), GAS_GET_IMPORT.to_string(), External::Function(0)),
+            ImportEntry::new(BCOS_MODULE_NAME.to_string(), GAS_SET_IMPORT.to_string(), External::Function(1)),
+            ImportEntry::new(BCOS_MODULE_NAME.to_string(), OUT_OF_GAS_IMPORT.to_string(), External::Function(2)),
+        ]);
+        let function_section = FunctionSection::with_entries(vec![Func::new(3)]);
+        let memory_section = MemorySection::with_entries(vec![MemoryType::new(1, None)]);
+        let code_section = CodeSection::with_bodies(vec![FuncBody::new(
+            vec![],
+            Instructions::new(vec![
+                Instruction::GetLocal(0),
+                Instruction::GrowMemory(0),
+                Instruction::Drop,
+                Instruction::End,
+            ]),
+        )]);
+
+        let module = Module::new(vec![
+            Section::Type(type_section),
+            Section::Import(import_section),
+            Section::Function(function_section),
+            Section::Memory(memory_section),
+            Section::Code(code_section),
+        ]);
+        parity_wasm::serialize(module).expect("test module must serialize")
+    }
+
+    #[test]
+    fn instrument_produces_a_module_that_still_validates() {
+        let code = module_with_param_and_memory_grow();
+        let instrumented = instrument(&code, &CostTable::default()).expect("instrumentation must succeed");
+
+        let engine = wasmtime::Engine::default();
+        wasmtime::Module::validate(&engine, &instrumented)
+            .expect("instrumented module with a param and memory.grow must still validate");
+    }
+}
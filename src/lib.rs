@@ -1,4 +1,7 @@
 mod fbei;
+mod gas_meter;
+mod module_cache;
+mod validation;
 
 use async_std::task;
 use evmc_vm::ffi::{evmc_call_kind, evmc_status_code};
@@ -7,21 +10,35 @@ use lazy_static::lazy_static;
 use log::{debug, error, info, log_enabled, Level};
 use std::sync::{Arc, Mutex, Once};
 use wasmtime::{
-    Caller, Config, Engine, Global, GlobalType, Linker, Module, Mutability, Store, Trap, Val,
-    ValType,
+    Caller, Config, Engine, InstanceAllocationStrategy, Linker, Module, PoolingAllocationConfig,
+    Store, Trap,
 };
 
 static START: Once = Once::new();
 const CONTRACT_MAIN: &str = "main";
 const CONTRACT_DEPLOY: &str = "deploy";
 const CONTRACT_HASH_TYPE: &str = "hash_type";
+/// Wall-clock ceiling on a single execution, in epoch ticks (see
+/// `EPOCH_TICK_INTERVAL` for how long a tick is). Gas bounds total work, not
+/// time, so a contract that's somehow metered-but-slow (e.g. stuck looping
+/// on cheap opcodes) still needs a hard stop independent of gas accounting.
+const EPOCH_DEADLINE_TICKS: u64 = 50;
+const EPOCH_TICK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
 lazy_static! {
+    static ref GAS_COST_TABLE: gas_meter::CostTable = gas_meter::CostTable::default();
+    static ref VALIDATION_LIMITS: validation::Limits = validation::Limits::default();
     static ref WASMTIME_ENGINE: Engine = {
         let mut config = Config::new();
         config
             .async_support(true)
             .cache_config_load_default()
-            .unwrap();
+            .unwrap()
+            .epoch_interruption(true);
+        // Reuse pre-reserved instance/memory slots instead of allocating a
+        // fresh instance from scratch for every single transaction.
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling.total_core_instances(1024).total_memories(1024);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
         match Engine::new(&config) {
             Ok(engine) => engine,
             Err(e) => {
@@ -29,12 +46,22 @@ lazy_static! {
             }
         }
     };
+    static ref MODULE_CACHE: module_cache::SharedModuleCache =
+        module_cache::SharedModuleCache::new(WASMTIME_ENGINE.clone());
+    // Gas now lives in `EnvironmentInterface`/store data rather than a
+    // store-owned `Global`, so one `Linker` (and its host-function table)
+    // can be built once and reused across every execution instead of being
+    // rebuilt per call just to rebind that global.
+    static ref LINKER: Linker<Arc<Mutex<EnvironmentInterface>>> = {
+        let mut linker = Linker::new(&WASMTIME_ENGINE);
+        prepare_imports(&mut linker);
+        linker
+    };
 }
 #[evmc_declare::evmc_declare_vm("bcos wasm", "fbwasm", "1.0.0-rc1")]
 pub struct BcosWasm;
 
 const BCOS_MODULE_NAME: &str = "bcos";
-const BCOS_GLOBAL_GAS_VAR: &str = "gas";
 
 fn has_wasm_preamble(data: &[u8]) -> bool {
     data.len() >= 8 && data[0..4] == [0x00, 0x61, 0x73, 0x6d]
@@ -44,6 +71,14 @@ fn has_wasm_version(data: &[u8], version: u8) -> bool {
     data.len() >= 8 && data[4..8] == [version, 0x00, 0x00, 0x00]
 }
 
+/// Whether `error` is the trap wasmtime raises when `Store::set_epoch_deadline`
+/// trips, as opposed to a trap the contract caused itself. Matched on message
+/// text rather than a structured code since this crate's `Trap` is the plain
+/// string-based kind used for host-function traps elsewhere in this file.
+fn is_epoch_deadline_trap(error: &anyhow::Error) -> bool {
+    error.to_string().contains("epoch deadline")
+}
+
 fn prepare_imports(linker: &mut Linker<Arc<Mutex<EnvironmentInterface>>>) {
     linker
         .func_wrap(
@@ -200,7 +235,7 @@ fn prepare_imports(linker: &mut Linker<Arc<Mutex<EnvironmentInterface>>>) {
              address_offset: u32,
              size: u32| {
                 let env_interface = caller.data().clone();
-                let env = env_interface.lock().unwrap();
+                let mut env = env_interface.lock().unwrap();
                 match env.get_code_size(&mut caller, address_offset, size) {
                     Ok(len) => Ok(len),
                     Err(e) => {
@@ -290,30 +325,240 @@ fn prepare_imports(linker: &mut Linker<Arc<Mutex<EnvironmentInterface>>>) {
              address_offset: u32,
              address_size: u32,
              data_offset: u32,
-             data_size: u32| {
+             data_size: u32|
+             -> Result<i32, Trap> {
                 let env_interface = caller.data().clone();
                 let mut env = env_interface.lock().unwrap();
-                match env.call(
-                    &mut caller,
-                    address_offset,
-                    address_size,
-                    data_offset,
-                    data_size,
-                ) {
-                    Ok(status) => match status {
-                        0 => Ok(0),
-                        _ => Err(Trap::new("call failed")),
-                    },
-                    Err(e) => {
-                        return Err(Trap::new(format!("trap, {}", e)));
-                    }
+                // Propagate the EVMC status (including reverts/failures) to
+                // the caller instead of trapping on anything nonzero, so a
+                // contract can observe and recover from a sub-call revert.
+                env.call(&mut caller, address_offset, address_size, data_offset, data_size)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "callCode",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             address_offset: u32,
+             address_size: u32,
+             data_offset: u32,
+             data_size: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.call_code(&mut caller, address_offset, address_size, data_offset, data_size)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "callDelegate",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             address_offset: u32,
+             address_size: u32,
+             data_offset: u32,
+             data_size: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.call_delegate(&mut caller, address_offset, address_size, data_offset, data_size)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "callStatic",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             address_offset: u32,
+             address_size: u32,
+             data_offset: u32,
+             data_size: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.call_static(&mut caller, address_offset, address_size, data_offset, data_size)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "create",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             data_offset: u32,
+             data_size: u32,
+             result_offset: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.create(&mut caller, data_offset, data_size, result_offset)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "create2",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             data_offset: u32,
+             data_size: u32,
+             salt_offset: u32,
+             result_offset: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.create2(&mut caller, data_offset, data_size, salt_offset, result_offset)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "selfDestruct",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, address_offset: u32| {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                match env.self_destruct(&mut caller, address_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getExternalBalance",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, address_offset: u32, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                match env.get_external_balance(&mut caller, address_offset, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getExternalCodeHash",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, address_offset: u32, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                match env.get_external_code_hash(&mut caller, address_offset, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "externalCodeCopy",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>,
+             address_offset: u32,
+             code_offset: u32,
+             result_offset: u32,
+             size: u32|
+             -> Result<i32, Trap> {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                env.external_code_copy(&mut caller, address_offset, code_offset, result_offset, size)
+                    .map_err(|e| Trap::new(format!("trap, {}", e)))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getBlockHash",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, number: i64, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let mut env = env_interface.lock().unwrap();
+                match env.get_block_hash(&mut caller, number, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getBlockCoinbase",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let env = env_interface.lock().unwrap();
+                match env.get_block_coinbase(&mut caller, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getBlockGasLimit",
+            |caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>| -> i64 {
+                let env_interface = caller.data();
+                env_interface.lock().unwrap().get_block_gas_limit()
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getBlockPrevRandao",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let env = env_interface.lock().unwrap();
+                match env.get_block_prev_randao(&mut caller, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
                 }
             },
         )
         .unwrap()
-        .func_wrap(BCOS_MODULE_NAME, "outOfGas", || -> Result<(), Trap> {
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getTxGasPrice",
+            |mut caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, result_offset: u32| {
+                let env_interface = caller.data().clone();
+                let env = env_interface.lock().unwrap();
+                match env.get_tx_gas_price(&mut caller, result_offset) {
+                    Err(e) => Err(Trap::new(format!("trap, {}", e))),
+                    _ => Ok(()),
+                }
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            "getGasLeft",
+            |caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>| -> i64 {
+                caller.data().lock().unwrap().get_gas_left()
+            },
+        )
+        .unwrap()
+        .func_wrap(BCOS_MODULE_NAME, gas_meter::OUT_OF_GAS_IMPORT, || -> Result<(), Trap> {
             Err(Trap::new("Out Of Gas"))
         })
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            gas_meter::GAS_GET_IMPORT,
+            |caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>| -> i64 {
+                caller.data().lock().unwrap().get_gas_left()
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            BCOS_MODULE_NAME,
+            gas_meter::GAS_SET_IMPORT,
+            |caller: Caller<'_, Arc<Mutex<EnvironmentInterface>>>, gas: i64| {
+                caller.data().lock().unwrap().set_gas_left(gas);
+            },
+        )
         .unwrap();
 }
 
@@ -344,6 +589,13 @@ impl evmc_vm::EvmcVm for BcosWasm {
         START.call_once(|| {
             env_logger::init();
             info!("wasm init");
+            // `Store::set_epoch_deadline` only counts down; something still
+            // has to advance `WASMTIME_ENGINE`'s epoch on a wall-clock
+            // schedule, or a deadline would never actually trip.
+            std::thread::spawn(|| loop {
+                std::thread::sleep(EPOCH_TICK_INTERVAL);
+                WASMTIME_ENGINE.increment_epoch();
+            });
         });
         let context = match context {
             Some(c) => c,
@@ -381,7 +633,21 @@ impl evmc_vm::EvmcVm for BcosWasm {
         }
 
         let env_interface = Arc::new(Mutex::new(EnvironmentInterface::new(context, message)));
-        let module = match Module::from_binary(&WASMTIME_ENGINE, code) {
+        // Instrument before compiling so gas accounting is enforced by the
+        // bytecode itself, not by whether the contract chose to decrement
+        // `bcos.gas` honestly.
+        let instrumented_code = match gas_meter::instrument(code, &GAS_COST_TABLE) {
+            Ok(instrumented) => instrumented,
+            Err(e) => {
+                error!("Failed to instrument contract for gas metering: {}", e);
+                return evmc_vm::ExecutionResult::new(
+                    evmc_status_code::EVMC_CONTRACT_VALIDATION_FAILURE,
+                    0,
+                    None,
+                );
+            }
+        };
+        let module = match MODULE_CACHE.get_or_compile(&instrumented_code, code, host_sm_crypto) {
             Ok(module) => module,
             Err(e) => {
                 error!("Failed to create wasmtime engine: {}", e);
@@ -394,18 +660,10 @@ impl evmc_vm::EvmcVm for BcosWasm {
         };
         let mut store: Store<Arc<Mutex<EnvironmentInterface>>> =
             Store::new(&WASMTIME_ENGINE, env_interface.clone());
-        let mut linker: Linker<Arc<Mutex<EnvironmentInterface>>> = Linker::new(&WASMTIME_ENGINE);
-        let ty = GlobalType::new(ValType::I64, Mutability::Var);
-        let global_gas = Global::new(&mut store, ty, Val::I64(message.gas())).unwrap();
-        env_interface
-            .lock()
-            .unwrap()
-            .set_gas_global(global_gas.clone());
-        prepare_imports(&mut linker);
-        // TODO: because the global owned by store is defined, the linker can not used to instantiate many modules
-        linker
-            .define(BCOS_MODULE_NAME, BCOS_GLOBAL_GAS_VAR, global_gas)
-            .unwrap();
+        // Gas bounds total metered work, not wall-clock time; the epoch
+        // deadline is the backstop against a contract that's merely slow
+        // (e.g. host-call-heavy but cheap-per-opcode by the cost table).
+        store.set_epoch_deadline(EPOCH_DEADLINE_TICKS);
         if message.kind() == evmc_call_kind::EVMC_CREATE {
             if !verify_contract(&module) {
                 error!("Contract code is not valid");
@@ -415,8 +673,20 @@ impl evmc_vm::EvmcVm for BcosWasm {
                     None,
                 );
             }
+            // Structural determinism check: floats, SIMD/atomics, multiple
+            // memories, a start section, or imports outside the known EEI
+            // surface all threaten cross-node consensus and must be
+            // rejected here, not merely at instantiation.
+            if let Err(e) = validation::validate_bytes(code, &VALIDATION_LIMITS) {
+                error!("Contract failed deterministic-wasm validation: {}", e);
+                return evmc_vm::ExecutionResult::new(
+                    evmc_status_code::EVMC_CONTRACT_VALIDATION_FAILURE,
+                    0,
+                    None,
+                );
+            }
         }
-        let instance = match linker.instantiate(&mut store, &module) {
+        let instance = match LINKER.instantiate(&mut store, &module) {
             Ok(instance) => instance,
             Err(e) => {
                 error!("Failed to instantiate wasmtime module: {}", e);
@@ -472,7 +742,11 @@ impl evmc_vm::EvmcVm for BcosWasm {
                     _ => false,
                 },
                 Err(e) => {
-                    error!("Failed to call hash function: {}", e);
+                    if is_epoch_deadline_trap(&e) {
+                        error!("Execution deadline exceeded calling hash function");
+                    } else {
+                        error!("Failed to call hash function: {}", e);
+                    }
                     return evmc_vm::ExecutionResult::new(
                         evmc_status_code::EVMC_WASM_TRAP,
                         0,
@@ -511,7 +785,11 @@ impl evmc_vm::EvmcVm for BcosWasm {
         match task::block_on(future) {
             Ok(ret) => ret,
             Err(e) => {
-                error!("Failed to call {} function: {}", call_name, e);
+                if is_epoch_deadline_trap(&e) {
+                    error!("Execution deadline exceeded calling {} function", call_name);
+                } else {
+                    error!("Failed to call {} function: {}", call_name, e);
+                }
                 return evmc_vm::ExecutionResult::new(evmc_status_code::EVMC_WASM_TRAP, 0, None);
             }
         };
@@ -520,7 +798,7 @@ impl evmc_vm::EvmcVm for BcosWasm {
         // get output from env_interface
         let output = env.get_output();
         if !env.reverted() {
-            let gas_left = env.get_gas_left(&mut store).unwrap();
+            let gas_left = env.get_gas_left();
             if message.kind() == evmc_call_kind::EVMC_CREATE {
                 evmc_vm::ExecutionResult::success(gas_left, Some(code))
             } else {
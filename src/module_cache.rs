@@ -0,0 +1,171 @@
+//! Compiled-module cache keyed by a content hash of the contract bytecode,
+//! so hot contracts are compiled once instead of on every `execute` call.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use libsm::sm3::hash::Sm3Hash;
+use tiny_keccak::{Hasher, Keccak};
+use wasmtime::{Engine, Module};
+
+/// Cache key: the content hash of the *instrumented* bytecode, tagged with
+/// the hash regime it was computed under so `isSMCrypto` and keccak
+/// contracts can never collide even if their digests happened to match.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+    digest: [u8; 32],
+    sm_crypto: bool,
+}
+
+fn hash_code(code: &[u8], sm_crypto: bool) -> CacheKey {
+    let digest = if sm_crypto {
+        let mut hasher = Sm3Hash::new(code);
+        hasher.get_hash()
+    } else {
+        let mut keccak = Keccak::v256();
+        let mut digest = [0u8; 32];
+        keccak.update(code);
+        keccak.finalize(&mut digest);
+        digest
+    };
+    CacheKey { digest, sm_crypto }
+}
+
+struct Entry {
+    module: Module,
+    size_bytes: usize,
+}
+
+/// A small LRU cache of compiled `Module`s, bounded both by entry count and
+/// by the approximate serialized size of what it holds. Also persists
+/// compiled artifacts to disk via `Module::serialize`/`Engine::deserialize`
+/// so a process restart doesn't cost a full recompile of every hot
+/// contract. Artifacts are written under `<user cache dir>/bcos-wasm/modules`,
+/// which is not the directory wasmtime's own compilation cache (enabled by
+/// `cache_config_load_default`) uses.
+pub struct ModuleCache {
+    engine: Engine,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes_used: usize,
+    order: Vec<CacheKey>,
+    entries: HashMap<CacheKey, Entry>,
+    disk_dir: Option<PathBuf>,
+}
+
+impl ModuleCache {
+    pub fn new(engine: Engine, max_entries: usize, max_bytes: usize, disk_dir: Option<PathBuf>) -> Self {
+        ModuleCache {
+            engine,
+            max_entries,
+            max_bytes,
+            bytes_used: 0,
+            order: Vec::new(),
+            entries: HashMap::new(),
+            disk_dir,
+        }
+    }
+
+    fn disk_path(&self, key: &CacheKey) -> Option<PathBuf> {
+        self.disk_dir.as_ref().map(|dir| {
+            let prefix = if key.sm_crypto { "sm" } else { "keccak" };
+            dir.join(format!("{}-{}.cwasm", prefix, hex::encode(key.digest)))
+        })
+    }
+
+    fn touch(&mut self, key: CacheKey) {
+        self.order.retain(|k| k != &key);
+        self.order.push(key);
+    }
+
+    fn evict_until_within_bounds(&mut self) {
+        while self.entries.len() > self.max_entries || self.bytes_used > self.max_bytes {
+            let Some(oldest) = (!self.order.is_empty()).then(|| self.order.remove(0)) else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.bytes_used -= entry.size_bytes;
+            }
+        }
+    }
+
+    /// Returns a compiled module for `code` (the instrumented bytecode),
+    /// compiling (and inserting) on a miss. `sm_crypto` must match the hash
+    /// regime `code`'s hash was/would be computed under. `pre_instrumentation_code`
+    /// is the original, un-instrumented contract bytes; it's only consulted
+    /// on a cache miss, to re-run deterministic-wasm validation against the
+    /// same data the primary deploy-time check sees, not against the
+    /// instrumentation pass's own output.
+    pub fn get_or_compile(
+        &mut self,
+        code: &[u8],
+        pre_instrumentation_code: &[u8],
+        sm_crypto: bool,
+    ) -> anyhow::Result<Module> {
+        let key = hash_code(code, sm_crypto);
+        if let Some(entry) = self.entries.get(&key) {
+            let module = entry.module.clone();
+            self.touch(key);
+            return Ok(module);
+        }
+
+        if let Some(path) = self.disk_path(&key) {
+            if let Ok(bytes) = std::fs::read(&path) {
+                if let Ok(module) = unsafe { Module::deserialize(&self.engine, &bytes) } {
+                    self.insert(key, module.clone(), bytes.len());
+                    return Ok(module);
+                }
+            }
+        }
+
+        // Cache misses are the only point every code path (deploy, and any
+        // future warm-start path that skips `execute`'s own EVMC_CREATE
+        // check) is guaranteed to pass through, so validate here too.
+        // Validated against `pre_instrumentation_code`, not `code`: `code`
+        // is already-instrumented bytecode, and validating that would check
+        // different data than the primary deploy-time check does.
+        crate::validation::validate_bytes(pre_instrumentation_code, &crate::VALIDATION_LIMITS)
+            .map_err(|e| anyhow::anyhow!("contract failed deterministic-wasm validation: {}", e))?;
+
+        let module = Module::from_binary(&self.engine, code)?;
+        let size_bytes = module.serialize().map(|bytes| bytes.len()).unwrap_or(code.len());
+        if let Some(path) = self.disk_path(&key) {
+            if let Ok(bytes) = module.serialize() {
+                let _ = std::fs::create_dir_all(path.parent().unwrap());
+                let _ = std::fs::write(&path, bytes);
+            }
+        }
+        self.insert(key, module.clone(), size_bytes);
+        Ok(module)
+    }
+
+    fn insert(&mut self, key: CacheKey, module: Module, size_bytes: usize) {
+        self.entries.insert(key, Entry { module, size_bytes });
+        self.bytes_used += size_bytes;
+        self.touch(key);
+        self.evict_until_within_bounds();
+    }
+}
+
+pub struct SharedModuleCache(Mutex<ModuleCache>);
+
+impl SharedModuleCache {
+    pub fn new(engine: Engine) -> Self {
+        const MAX_ENTRIES: usize = 256;
+        const MAX_BYTES: usize = 512 * 1024 * 1024;
+        let disk_dir = dirs_next::cache_dir().map(|dir| dir.join("bcos-wasm").join("modules"));
+        SharedModuleCache(Mutex::new(ModuleCache::new(engine, MAX_ENTRIES, MAX_BYTES, disk_dir)))
+    }
+
+    pub fn get_or_compile(
+        &self,
+        code: &[u8],
+        pre_instrumentation_code: &[u8],
+        sm_crypto: bool,
+    ) -> anyhow::Result<Module> {
+        self.0
+            .lock()
+            .unwrap()
+            .get_or_compile(code, pre_instrumentation_code, sm_crypto)
+    }
+}
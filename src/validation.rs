@@ -0,0 +1,273 @@
+//! Deterministic-wasm validation: a purely structural pass over a contract's
+//! module so two honest nodes always agree on accept/reject, independent of
+//! what the local wasmtime build happens to support. Runs on `EVMC_CREATE`
+//! (and, because it's cheap relative to compilation, again whenever the
+//! compiled-module cache compiles a module it holds neither in memory nor on
+//! disk; modules loaded from disk are not validated again).
+use parity_wasm::elements::{External, Instruction, Module, Type, ValueType};
+
+use crate::BCOS_MODULE_NAME;
+
+// `parity_wasm` only understands the MVP instruction set plus
+// sign-extension ops; it simply fails to parse SIMD (`v128`) and
+// threads/atomics opcodes. Combined with `gas_meter::instrument`'s use of
+// the same parser, that means such modules are already rejected by the time
+// anything in this file runs, so `validate` only needs to special-case
+// floats explicitly.
+
+/// Host functions `prepare_imports` actually wires up. An import outside
+/// this list (or outside the `bcos` module) has no host-side implementation
+/// and would behave differently node to node, so it's rejected up front.
+const EEI_ALLOW_LIST: &[&str] = &[
+    "finish",
+    "revert",
+    "getAddress",
+    "getCallDataSize",
+    "getCallData",
+    "setStorage",
+    "getStorage",
+    "getCaller",
+    "getTxOrigin",
+    "getExternalCodeSize",
+    "getBlockNumber",
+    "getBlockTimestamp",
+    "log",
+    "getReturnDataSize",
+    "getReturnData",
+    "call",
+    "callCode",
+    "callDelegate",
+    "callStatic",
+    "create",
+    "create2",
+    "selfDestruct",
+    "getExternalBalance",
+    "getExternalCodeHash",
+    "externalCodeCopy",
+    "getBlockHash",
+    "getBlockCoinbase",
+    "getBlockGasLimit",
+    "getBlockPrevRandao",
+    "getTxGasPrice",
+    "getGasLeft",
+    "outOfGas",
+    crate::gas_meter::GAS_GET_IMPORT,
+    crate::gas_meter::GAS_SET_IMPORT,
+    crate::gas_meter::OUT_OF_GAS_IMPORT,
+];
+
+/// `gas_meter::instrument` rewrites every function to charge gas through
+/// these three host calls and refuses a module that does not import them.
+/// So unlike the rest of `EEI_ALLOW_LIST`, this subset isn't just permitted —
+/// `validate` requires it on every deployed contract too.
+const REQUIRED_GAS_IMPORTS: &[&str] = &[
+    crate::gas_meter::GAS_GET_IMPORT,
+    crate::gas_meter::GAS_SET_IMPORT,
+    crate::gas_meter::OUT_OF_GAS_IMPORT,
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_memory_pages: u32,
+    pub max_table_size: u32,
+    pub max_functions: u32,
+    pub max_locals_per_function: u32,
+    pub max_code_size_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_memory_pages: 512, // 32 MiB
+            max_table_size: 4096,
+            max_functions: 8192,
+            max_locals_per_function: 512,
+            max_code_size_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    Malformed,
+    FloatingPoint,
+    Simd,
+    SharedMemoryOrAtomic,
+    MultipleMemories,
+    StartSection,
+    DisallowedImport { module: String, field: String },
+    MissingGasImports,
+    MemoryTooLarge,
+    TableTooLarge,
+    TooManyFunctions,
+    TooManyLocals,
+    CodeTooLarge,
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Malformed => write!(f, "module is not well-formed wasm"),
+            ValidationError::FloatingPoint => write!(f, "module uses floating point"),
+            ValidationError::Simd => write!(f, "module uses SIMD"),
+            ValidationError::SharedMemoryOrAtomic => write!(f, "module uses shared memory or atomics"),
+            ValidationError::MultipleMemories => write!(f, "module declares more than one memory"),
+            ValidationError::StartSection => write!(f, "module declares a start section"),
+            ValidationError::DisallowedImport { module, field } => {
+                write!(f, "disallowed import {}.{}", module, field)
+            }
+            ValidationError::MissingGasImports => {
+                write!(f, "module does not import the required gasGet/gasSet/outOfGas triple")
+            }
+            ValidationError::MemoryTooLarge => write!(f, "declared memory exceeds the configured limit"),
+            ValidationError::TableTooLarge => write!(f, "declared table exceeds the configured limit"),
+            ValidationError::TooManyFunctions => write!(f, "module declares too many functions"),
+            ValidationError::TooManyLocals => write!(f, "a function declares too many locals"),
+            ValidationError::CodeTooLarge => write!(f, "code section exceeds the configured limit"),
+        }
+    }
+}
+
+fn is_float_type(value_type: ValueType) -> bool {
+    matches!(value_type, ValueType::F32 | ValueType::F64)
+}
+
+fn is_float_or_simd_instruction(instruction: &Instruction) -> Result<(), ValidationError> {
+    use Instruction::*;
+    match instruction {
+        F32Load(..) | F32Store(..) | F64Load(..) | F64Store(..) | F32Const(_) | F64Const(_)
+        | I32TruncSF32 | I32TruncUF32 | I32TruncSF64 | I32TruncUF64 | I64TruncSF32
+        | I64TruncUF32 | I64TruncSF64 | I64TruncUF64 | F32ConvertSI32 | F32ConvertUI32
+        | F32ConvertSI64 | F32ConvertUI64 | F64ConvertSI32 | F64ConvertUI32 | F64ConvertSI64
+        | F64ConvertUI64 | F32DemoteF64 | F64PromoteF32 | F32ReinterpretI32
+        | F64ReinterpretI64 | I32ReinterpretF32 | I64ReinterpretF64 | F32Abs | F32Neg
+        | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt | F32Add | F32Sub | F32Mul
+        | F32Div | F32Min | F32Max | F32Copysign | F64Abs | F64Neg | F64Ceil | F64Floor
+        | F64Trunc | F64Nearest | F64Sqrt | F64Add | F64Sub | F64Mul | F64Div | F64Min
+        | F64Max | F64Copysign | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge | F64Eq
+        | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => Err(ValidationError::FloatingPoint),
+        _ => Ok(()),
+    }
+}
+
+/// Purely structural check: reject anything that could make two otherwise
+/// identical nodes disagree about execution result.
+pub fn validate(module: &Module, limits: &Limits) -> Result<(), ValidationError> {
+    if module.start_section().is_some() {
+        return Err(ValidationError::StartSection);
+    }
+
+    if let Some(memory_section) = module.memory_section() {
+        if memory_section.entries().len() > 1 {
+            return Err(ValidationError::MultipleMemories);
+        }
+        for entry in memory_section.entries() {
+            if entry.limits().shared() {
+                return Err(ValidationError::SharedMemoryOrAtomic);
+            }
+            if entry.limits().initial() > limits.max_memory_pages
+                || entry.limits().maximum().unwrap_or(u32::MAX) > limits.max_memory_pages
+            {
+                return Err(ValidationError::MemoryTooLarge);
+            }
+        }
+    }
+
+    if let Some(table_section) = module.table_section() {
+        for entry in table_section.entries() {
+            if entry.limits().initial() > limits.max_table_size
+                || entry.limits().maximum().unwrap_or(u32::MAX) > limits.max_table_size
+            {
+                return Err(ValidationError::TableTooLarge);
+            }
+        }
+    }
+
+    let mut imported_gas_functions = [false; 3];
+    if let Some(import_section) = module.import_section() {
+        for entry in import_section.entries() {
+            match entry.external() {
+                External::Function(_) => {
+                    if entry.module() != BCOS_MODULE_NAME
+                        || !EEI_ALLOW_LIST.contains(&entry.field())
+                    {
+                        return Err(ValidationError::DisallowedImport {
+                            module: entry.module().to_string(),
+                            field: entry.field().to_string(),
+                        });
+                    }
+                    if entry.module() == BCOS_MODULE_NAME {
+                        if let Some(i) = REQUIRED_GAS_IMPORTS.iter().position(|f| *f == entry.field()) {
+                            imported_gas_functions[i] = true;
+                        }
+                    }
+                }
+                External::Global(_) => {
+                    // Gas now lives in host-owned store data, reached via
+                    // the `gasGet`/`gasSet` calls above; no imported global
+                    // is ever legitimate.
+                    return Err(ValidationError::DisallowedImport {
+                        module: entry.module().to_string(),
+                        field: entry.field().to_string(),
+                    });
+                }
+                _ => {
+                    // The linker only provides host functions, so an
+                    // imported table or memory is never legitimate either.
+                    return Err(ValidationError::DisallowedImport {
+                        module: entry.module().to_string(),
+                        field: entry.field().to_string(),
+                    });
+                }
+            }
+        }
+    }
+    if imported_gas_functions.iter().any(|imported| !imported) {
+        return Err(ValidationError::MissingGasImports);
+    }
+
+    if let Some(type_section) = module.type_section() {
+        for ty in type_section.types() {
+            let Type::Function(function_type) = ty;
+            if function_type.params().iter().any(|t| is_float_type(*t))
+                || function_type.results().iter().any(|t| is_float_type(*t))
+            {
+                return Err(ValidationError::FloatingPoint);
+            }
+        }
+    }
+
+    if let Some(code_section) = module.code_section() {
+        if code_section.bodies().len() as u32 > limits.max_functions {
+            return Err(ValidationError::TooManyFunctions);
+        }
+        let mut total_code_size = 0usize;
+        for body in code_section.bodies() {
+            let locals_count: u32 = body.locals().iter().map(|l| l.count()).sum();
+            if locals_count > limits.max_locals_per_function {
+                return Err(ValidationError::TooManyLocals);
+            }
+            if body.locals().iter().any(|l| is_float_type(l.value_type())) {
+                return Err(ValidationError::FloatingPoint);
+            }
+            total_code_size += parity_wasm::serialize(body.clone())
+                .map_err(|_| ValidationError::Malformed)?
+                .len();
+            for instruction in body.code().elements() {
+                is_float_or_simd_instruction(instruction)?;
+            }
+        }
+        if total_code_size > limits.max_code_size_bytes {
+            return Err(ValidationError::CodeTooLarge);
+        }
+    }
+
+    Ok(())
+}
+
+/// Convenience wrapper for callers (deploy-time checks, cache-insert checks)
+/// that only have raw bytes, not an already-parsed `Module`.
+pub fn validate_bytes(code: &[u8], limits: &Limits) -> Result<(), ValidationError> {
+    let module: Module = parity_wasm::deserialize_buffer(code).map_err(|_| ValidationError::Malformed)?;
+    validate(&module, limits)
+}